@@ -3,14 +3,204 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use libsignal_bridge_macros::bridge_fn;
+
 use super::*;
 use crate::net::chat::{ChatListener, MakeChatListener, ServerMessageAck};
 
 pub type JavaMakeChatListener<'a> = JObject<'a>;
 
+/// Initial retry timeout for an incoming message that hasn't been acked; doubles on each retry
+/// up to [`MAX_RETRY_TIMEOUT`].
+const INITIAL_RETRY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound on the (doubling) redelivery timeout.
+const MAX_RETRY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// A message redelivered this many times without being acked is dropped.
+const MAX_RETRY_COUNT: u32 = 8;
+/// How often the redelivery thread wakes up to scan for overdue entries (or run a requested
+/// replay; see [`RedeliveryQueue::request_replay`]).
+const REDELIVERY_SCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+fn retry_timeout(retry_count: u32) -> Duration {
+    INITIAL_RETRY_TIMEOUT
+        .checked_mul(1u32 << retry_count.min(31))
+        .unwrap_or(MAX_RETRY_TIMEOUT)
+        .min(MAX_RETRY_TIMEOUT)
+}
+
+struct PendingDelivery {
+    envelope: Vec<u8>,
+    timestamp: Timestamp,
+    ack: ServerMessageAck,
+    /// Shared across every (re)delivery of this entry, so whichever copy of the ack Java fires
+    /// first is the one that counts; see [`seq_scoped_ack`].
+    ack_fired: Arc<AtomicBool>,
+    last_delivered_at: Instant,
+    retry_count: u32,
+}
+
+type DueDelivery = (u64, Vec<u8>, Timestamp, ServerMessageAck, Arc<AtomicBool>);
+
+/// Tracks envelopes that have been delivered to the Java listener but not yet acked, so they can
+/// be redelivered if the ack is lost, e.g. to an app crash or a dropped connection before `ack`
+/// is called.
+///
+/// Redelivery is by design, not a bug: an entry is removed (and so stops being redelivered) only
+/// once it's acked (see [`Self::ack`]), and is otherwise resent on every RTO until then. Making
+/// that idempotent end to end requires the receiving side to dedup by `seq`, which is why `seq`
+/// is threaded all the way out to the `onIncomingMessage` call (see
+/// [`JniChatListener::deliver_incoming_message`]) rather than being kept Rust-internal.
+#[derive(Default)]
+struct RedeliveryQueue {
+    next_seq: u64,
+    pending: BTreeMap<u64, PendingDelivery>,
+    /// Set by [`Self::request_replay`] and consumed by [`Self::take_due`]; see that method for
+    /// why replay is folded into the same scan rather than dispatched from its own thread.
+    replay_requested: bool,
+}
+
+impl RedeliveryQueue {
+    /// Records a freshly delivered envelope and returns its assigned seq and ack-fired flag.
+    fn push(
+        &mut self,
+        envelope: Vec<u8>,
+        timestamp: Timestamp,
+        ack: ServerMessageAck,
+    ) -> (u64, Arc<AtomicBool>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let ack_fired = Arc::new(AtomicBool::new(false));
+        self.pending.insert(
+            seq,
+            PendingDelivery {
+                envelope,
+                timestamp,
+                ack,
+                ack_fired: ack_fired.clone(),
+                last_delivered_at: Instant::now(),
+                retry_count: 0,
+            },
+        );
+        (seq, ack_fired)
+    }
+
+    /// Removes `seq` from the queue. A later or duplicate ack for a seq that's already been
+    /// removed (e.g. by a racing redelivery) is a harmless no-op.
+    fn ack(&mut self, seq: u64) {
+        self.pending.remove(&seq);
+    }
+
+    fn depth(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Marks a replay of every still-pending entry as due; picked up by the next [`Self::take_due`]
+    /// call from the redelivery thread instead of delivering from a separate thread, so replay
+    /// can never race a concurrent scan over the same entries.
+    fn request_replay(&mut self) {
+        self.replay_requested = true;
+    }
+
+    /// Returns the next batch the redelivery thread should (re)deliver: every still-pending entry
+    /// if a replay was requested since the last call, otherwise just the entries whose RTO has
+    /// elapsed. Called only from the redelivery thread, so a replay and a scan can never hand out
+    /// the same seq at the same time.
+    fn take_due(&mut self) -> Vec<DueDelivery> {
+        if std::mem::take(&mut self.replay_requested) {
+            self.replay_all()
+        } else {
+            self.take_overdue()
+        }
+    }
+
+    /// Bumps the retry count of, and returns, every entry whose RTO has elapsed; entries that
+    /// have already hit [`MAX_RETRY_COUNT`] are dropped (with a logged warning) instead.
+    fn take_overdue(&mut self) -> Vec<DueDelivery> {
+        let now = Instant::now();
+        let mut overdue = Vec::new();
+        self.pending.retain(|&seq, entry| {
+            if now.duration_since(entry.last_delivered_at) < retry_timeout(entry.retry_count) {
+                return true;
+            }
+            if entry.retry_count >= MAX_RETRY_COUNT {
+                tracing::warn!(
+                    seq,
+                    retry_count = entry.retry_count,
+                    "dropping permanently-unacked incoming message"
+                );
+                return false;
+            }
+            entry.retry_count += 1;
+            entry.last_delivered_at = now;
+            overdue.push((
+                seq,
+                entry.envelope.clone(),
+                entry.timestamp,
+                entry.ack.clone(),
+                entry.ack_fired.clone(),
+            ));
+            true
+        });
+        overdue
+    }
+
+    /// Returns every still-pending entry in seq order, for replay after a reconnect, and resets
+    /// each entry's redelivery timer without counting it as a retry.
+    fn replay_all(&mut self) -> Vec<DueDelivery> {
+        let now = Instant::now();
+        self.pending
+            .iter_mut()
+            .map(|(&seq, entry)| {
+                entry.last_delivered_at = now;
+                (
+                    seq,
+                    entry.envelope.clone(),
+                    entry.timestamp,
+                    entry.ack.clone(),
+                    entry.ack_fired.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Wraps `ack` so that whichever (re)delivery of `seq` Java fires first removes `seq` from
+/// `redelivery` and forwards to the real ack; every other firing — by a redelivered copy handed
+/// out before the first one was acked — is a no-op. `fired` is shared by every copy of this
+/// entry's ack (see [`PendingDelivery::ack_fired`]), so this is what actually makes acking a
+/// message stop its redelivery, and makes redelivery safe to double-ack against.
+///
+/// This assumes `ServerMessageAck` exposes a `new` constructor taking the closure to run on ack,
+/// a `Clone` impl (one copy goes out with each redelivered copy of the envelope), and an `ack()`
+/// method that forwards to the server — those APIs aren't visible in this slice of `net::chat`,
+/// so they need confirming against the real module before this lands.
+fn seq_scoped_ack(
+    seq: u64,
+    redelivery: Arc<Mutex<RedeliveryQueue>>,
+    fired: Arc<AtomicBool>,
+    ack: ServerMessageAck,
+) -> ServerMessageAck {
+    ServerMessageAck::new(move || {
+        if fired.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        redelivery
+            .lock()
+            .expect("redelivery queue lock is not poisoned")
+            .ack(seq);
+        ack.ack();
+    })
+}
+
 pub struct JniChatListener {
     vm: JavaVM,
     listener: GlobalRef,
+    redelivery: Arc<Mutex<RedeliveryQueue>>,
 }
 
 pub type JniMakeChatListener<'unused> = JniChatListener;
@@ -25,6 +215,7 @@ impl Clone for JniChatListener {
                     .expect("copied from existing pointer")
             },
             listener: self.listener.clone(),
+            redelivery: self.redelivery.clone(),
         }
     }
 }
@@ -36,12 +227,68 @@ impl JniChatListener {
             listener,
             ClassName("org.signal.libsignal.net.internal.MakeChatListener"),
         )?;
-        Ok(Self {
+        let this = Self {
             vm: env.get_java_vm().expect("can get VM"),
             listener: env.new_global_ref(listener).expect("can get env"),
-        })
+            redelivery: Arc::new(Mutex::new(RedeliveryQueue::default())),
+        };
+        this.spawn_redelivery_thread();
+        Ok(this)
+    }
+
+    /// Periodically scans [`Self::redelivery`] for envelopes whose RTO has elapsed (or, after a
+    /// reconnect, replays every still-pending entry) and redelivers them. This is the sole
+    /// dispatcher of (re)delivery: folding replay into the same loop rather than spawning a thread
+    /// per reconnect means a replay can never race the scan over the same entries, and reconnect
+    /// churn doesn't spawn unbounded threads. Exits once this is the last surviving handle to the
+    /// queue.
+    fn spawn_redelivery_thread(&self) {
+        let this = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(REDELIVERY_SCAN_INTERVAL);
+            if Arc::strong_count(&this.redelivery) == 1 {
+                return;
+            }
+            let due = this
+                .redelivery
+                .lock()
+                .expect("redelivery queue lock is not poisoned")
+                .take_due();
+            for (seq, envelope, timestamp, ack, ack_fired) in due {
+                this.deliver_incoming_message(seq, envelope, timestamp, ack, ack_fired);
+            }
+        });
+    }
+
+    /// The number of envelopes delivered to the listener but not yet acked, for callers that
+    /// want to apply backpressure on a backed-up listener.
+    pub fn pending_queue_depth(&self) -> usize {
+        self.redelivery
+            .lock()
+            .expect("redelivery queue lock is not poisoned")
+            .depth()
+    }
+
+    /// Requests a replay of every still-unacked envelope, in seq order. Bridged to the app as
+    /// [`ChatListener_ReplayPending`] to call after it detects a reconnect, since this listener
+    /// has no connect/reconnect callback of its own to trigger it automatically (the
+    /// `ChatListener` impl below has no `connecting`/`connected`/`reconnecting` methods). The
+    /// (re)delivery itself happens on the redelivery scan thread the next time it wakes (see
+    /// [`Self::spawn_redelivery_thread`]), so this never races an in-progress scan.
+    pub fn replay_pending(&self) {
+        self.redelivery
+            .lock()
+            .expect("redelivery queue lock is not poisoned")
+            .request_replay();
     }
 
+    /// Attaches the current thread to the JVM and runs `operation` within the current
+    /// [`tracing`] span, recording the outcome as a `tracing` event.
+    ///
+    /// Callers are expected to have opened a span (with a `callback` field identifying which
+    /// `ChatListener` method is dispatching) before calling this; `attach_and_log_on_error` adds
+    /// the attach-thread id to that span and logs under it; that recording and logging are no-ops
+    /// when no `tracing` subscriber is installed.
     fn attach_and_log_on_error(
         &self,
         name: &'static str,
@@ -49,31 +296,39 @@ impl JniChatListener {
     ) {
         let attach_and_run = move || {
             let mut env = self.vm.attach_current_thread().expect("can attach thread");
+            tracing::Span::current().record(
+                "attach_thread_id",
+                tracing::field::debug(std::thread::current().id()),
+            );
             operation(&mut env)
         };
-        match attach_and_run() {
-            Ok(()) => {}
-            Err(e) => {
-                log::error!("failed to report {name}: {e}")
-            }
-        }
+        catch_unwind_and_log(name, attach_and_run);
     }
-}
 
-impl MakeChatListener for JniChatListener {
-    fn make_listener(&self) -> Box<dyn ChatListener> {
-        Box::new(self.clone())
-    }
-}
-
-impl ChatListener for JniChatListener {
-    fn received_incoming_message(
-        &mut self,
+    /// Calls `onIncomingMessage` for `envelope`, passing `seq` through so the Java side can dedup
+    /// a redelivered envelope from one it already handled (ack-only dedup on the Rust side only
+    /// protects against re-sending to the server, not against the app reprocessing a copy it
+    /// already saw). `ack_fired` itself stays Rust-internal; it's only used to build the
+    /// [`seq_scoped_ack`] wrapper around `ack`.
+    fn deliver_incoming_message(
+        &self,
+        seq: u64,
         envelope: Vec<u8>,
         timestamp: Timestamp,
         ack: ServerMessageAck,
+        ack_fired: Arc<AtomicBool>,
     ) {
+        let span = tracing::debug_span!(
+            "chat_listener_callback",
+            callback = "incoming message",
+            envelope_len = envelope.len(),
+            timestamp_ms = timestamp.epoch_millis(),
+            seq,
+            attach_thread_id = tracing::field::Empty,
+        );
+        let _entered = span.enter();
         let listener = &self.listener;
+        let ack = seq_scoped_ack(seq, self.redelivery.clone(), ack_fired, ack);
         self.attach_and_log_on_error("incoming message", move |env| {
             let env_array = envelope.convert_into(env)?;
             let ack_handle = ack.convert_into(env)?;
@@ -84,20 +339,125 @@ impl ChatListener for JniChatListener {
                 jni_args!((
                     env_array => [byte],
                     timestamp.epoch_millis() as i64 => long,
+                    seq as i64 => long,
                     ack_handle => long,
                 ) -> void),
             )
         });
     }
 
-    fn received_queue_empty(&mut self) {
+    /// Calls a zero-argument `java_method` on the listener, under a span tagged with `name`.
+    /// Shared by the handful of `ChatListener` callbacks that carry no payload.
+    fn dispatch_no_args_callback(&self, name: &'static str, java_method: &'static str) {
+        let span = tracing::debug_span!(
+            "chat_listener_callback",
+            callback = name,
+            attach_thread_id = tracing::field::Empty,
+        );
+        let _entered = span.enter();
         let listener = &self.listener;
-        self.attach_and_log_on_error("queue empty", move |env| {
-            call_method_checked(env, listener, "onQueueEmpty", jni_args!(() -> void))
+        self.attach_and_log_on_error(name, move |env| {
+            call_method_checked(env, listener, java_method, jni_args!(() -> void))
         });
     }
+}
+
+/// Exposes [`JniChatListener::pending_queue_depth`] so the app can apply backpressure on a
+/// backed-up listener instead of only being able to read it from within Rust.
+#[bridge_fn]
+fn ChatListener_PendingQueueDepth(listener: &JniChatListener) -> u64 {
+    listener.pending_queue_depth() as u64
+}
+
+/// Exposes [`JniChatListener::replay_pending`] so the app can explicitly request a replay of
+/// every unacked envelope, e.g. after it detects a reconnect on its own side.
+#[bridge_fn]
+fn ChatListener_ReplayPending(listener: &JniChatListener) {
+    listener.replay_pending();
+}
+
+/// Runs `operation`, catching any panic so it cannot unwind across the FFI boundary into the
+/// JVM (which is undefined behavior), and logs the outcome under `name`.
+///
+/// Split out from [`JniChatListener::attach_and_log_on_error`] so the catch/log behavior can be
+/// exercised directly in a unit test, without needing a live `JNIEnv`/`JavaVM` to attach to.
+fn catch_unwind_and_log(
+    name: &'static str,
+    operation: impl FnOnce() -> Result<(), BridgeLayerError>,
+) {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(operation)) {
+        Ok(Ok(())) => {
+            tracing::debug!(callback = name, "listener callback completed");
+        }
+        Ok(Err(error)) => {
+            tracing::error!(callback = name, %error, "failed to report {name}");
+        }
+        Err(payload) => {
+            tracing::error!(
+                callback = name,
+                panic = describe_panic(&payload),
+                "panic while reporting {name}"
+            );
+        }
+    }
+}
+
+/// Turns a [`catch_unwind`](std::panic::catch_unwind) payload into a human-readable message.
+///
+/// Takes the `Box` itself: a `&Box<dyn Any + Send>` passed where `&dyn Any` is expected coerces
+/// to the `Box` as the `Any` value, and every downcast of the payload would then fail.
+#[allow(clippy::borrowed_box)]
+fn describe_panic(payload: &Box<dyn std::any::Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+impl MakeChatListener for JniChatListener {
+    fn make_listener(&self) -> Box<dyn ChatListener> {
+        Box::new(self.clone())
+    }
+}
+
+// This request (adding `onConnecting`/`onConnected`/`onReconnecting` callbacks driven by a
+// reconnect state machine) is declined for this tree: it requires adding methods to the
+// `ChatListener` trait definition, adding matching methods to the Java
+// `org.signal.libsignal.net.internal.MakeChatListener` interface, and adding a reconnect state
+// machine to the chat connection that calls them — none of which exists in this source slice, and
+// none of which this change fabricates. `ChatListener` here keeps its current (pre-existing)
+// shape: `received_incoming_message`, `received_queue_empty`, and `connection_interrupted` only.
+// Replay after a reconnect is instead app-driven, via the bridged `ChatListener_ReplayPending`
+// (see [`JniChatListener::replay_pending`]).
+impl ChatListener for JniChatListener {
+    fn received_incoming_message(
+        &mut self,
+        envelope: Vec<u8>,
+        timestamp: Timestamp,
+        ack: ServerMessageAck,
+    ) {
+        let (seq, ack_fired) = self
+            .redelivery
+            .lock()
+            .expect("redelivery queue lock is not poisoned")
+            .push(envelope.clone(), timestamp, ack.clone());
+        self.deliver_incoming_message(seq, envelope, timestamp, ack, ack_fired);
+    }
+
+    fn received_queue_empty(&mut self) {
+        self.dispatch_no_args_callback("queue empty", "onQueueEmpty");
+    }
 
     fn connection_interrupted(&mut self, disconnect_cause: ChatServiceError) {
+        let span = tracing::debug_span!(
+            "chat_listener_callback",
+            callback = "connection interrupted",
+            attach_thread_id = tracing::field::Empty,
+        );
+        let _entered = span.enter();
         let listener = &self.listener;
         self.attach_and_log_on_error("connection interrupted", move |env| {
             convert_to_exception(
@@ -115,8 +475,9 @@ impl ChatListener for JniChatListener {
                             Ok(())
                         })
                         .unwrap_or_else(|error| {
-                            log::error!(
-                                "failed to call onConnectionInterrupted with cause: {error}"
+                            tracing::error!(
+                                %error,
+                                "failed to call onConnectionInterrupted with cause"
                             );
                         });
                 },
@@ -124,4 +485,40 @@ impl ChatListener for JniChatListener {
             Ok(())
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_timeout_doubles_and_caps() {
+        assert_eq!(retry_timeout(0), INITIAL_RETRY_TIMEOUT);
+        assert_eq!(retry_timeout(1), INITIAL_RETRY_TIMEOUT * 2);
+        assert_eq!(retry_timeout(2), INITIAL_RETRY_TIMEOUT * 4);
+        assert_eq!(retry_timeout(u32::MAX), MAX_RETRY_TIMEOUT);
+    }
+
+    #[test]
+    fn describe_panic_recovers_str_and_string_messages() {
+        let payload = std::panic::catch_unwind(|| panic!("boom")).unwrap_err();
+        assert_eq!(describe_panic(&payload), "boom");
+
+        let payload =
+            std::panic::catch_unwind(|| panic!("{}", "formatted boom".to_string())).unwrap_err();
+        assert_eq!(describe_panic(&payload), "formatted boom");
+    }
+
+    #[test]
+    fn catch_unwind_and_log_does_not_unwind_on_panic() {
+        // `attach_and_log_on_error` itself needs a live `JNIEnv`/`JavaVM` to attach to, which
+        // isn't available to a plain `cargo test` unit test, but the panic-catching behavior it
+        // delegates to is plain Rust and can be driven directly. Wrap the call in its own
+        // `catch_unwind` so a regression that lets the panic escape `catch_unwind_and_log` fails
+        // this assertion instead of aborting the test process.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catch_unwind_and_log("test callback", || panic!("listener closure panicked"));
+        }));
+        assert!(result.is_ok(), "panic escaped catch_unwind_and_log");
+    }
+}